@@ -0,0 +1,47 @@
+use axum::{
+    extract::{Request, State},
+    http::header,
+    middleware::Next,
+    response::Response,
+};
+use std::sync::Arc;
+
+use crate::error::ApiError;
+use crate::repository::DebianArchive;
+
+/// Rejects requests without a valid `Authorization: Bearer <token>` header.
+/// Authentication is disabled when no `api_tokens` are configured.
+pub async fn require_api_token(
+    State(archive): State<Arc<DebianArchive>>,
+    request: Request,
+    next: Next,
+) -> Result<Response, ApiError> {
+    if archive.api_tokens.is_empty() {
+        return Ok(next.run(request).await);
+    }
+
+    let token = request
+        .headers()
+        .get(header::AUTHORIZATION)
+        .and_then(|value| value.to_str().ok())
+        .and_then(|value| value.strip_prefix("Bearer "))
+        .ok_or(ApiError::Unauthorized)?;
+
+    // compare against every token so the response time doesn't reveal which one matched
+    let authorized = archive
+        .api_tokens
+        .iter()
+        .fold(false, |found, configured| found | constant_time_eq(configured.as_bytes(), token.as_bytes()));
+    if !authorized {
+        return Err(ApiError::Unauthorized);
+    }
+
+    Ok(next.run(request).await)
+}
+
+fn constant_time_eq(a: &[u8], b: &[u8]) -> bool {
+    if a.len() != b.len() {
+        return false;
+    }
+    a.iter().zip(b).fold(0, |difference, (x, y)| difference | (x ^ y)) == 0
+}
@@ -0,0 +1,113 @@
+use axum::{
+    http::{header, HeaderValue, StatusCode},
+    response::{IntoResponse, Json, Response},
+};
+use serde_json::json;
+use std::fmt;
+use std::io;
+
+/// Errors returned by the JSON API. Every variant serializes as
+/// `{"error": "<message>", "code": "<code>"}`, where `code` is stable for clients to match on.
+#[derive(Debug)]
+pub enum ApiError {
+    InvalidPath,
+    InvalidPackageFilename,
+    PackageConflict,
+    MissingPackageFile,
+    InvalidMultipart(String),
+    NotADebianPackage,
+    InvalidRequestBody(String),
+    RequestBodyTooLarge,
+    InvalidQuery(String),
+    InvalidDistribution(Vec<String>),
+    DistributionExists(String),
+    DistributionNotFound(String),
+    LastDistribution(String),
+    Unauthorized,
+    Io(io::Error),
+}
+
+impl ApiError {
+    pub fn status(&self) -> StatusCode {
+        match self {
+            ApiError::InvalidPath
+            | ApiError::InvalidPackageFilename
+            | ApiError::MissingPackageFile
+            | ApiError::InvalidMultipart(_)
+            | ApiError::NotADebianPackage
+            | ApiError::InvalidRequestBody(_)
+            | ApiError::InvalidQuery(_)
+            | ApiError::InvalidDistribution(_) => StatusCode::BAD_REQUEST,
+            ApiError::DistributionExists(_) | ApiError::LastDistribution(_) => StatusCode::CONFLICT,
+            ApiError::DistributionNotFound(_) => StatusCode::NOT_FOUND,
+            ApiError::PackageConflict => StatusCode::CONFLICT,
+            ApiError::RequestBodyTooLarge => StatusCode::PAYLOAD_TOO_LARGE,
+            ApiError::Unauthorized => StatusCode::UNAUTHORIZED,
+            ApiError::Io(_) => StatusCode::INTERNAL_SERVER_ERROR,
+        }
+    }
+
+    pub fn code(&self) -> &'static str {
+        match self {
+            ApiError::InvalidPath => "invalid_path",
+            ApiError::InvalidPackageFilename => "invalid_package_filename",
+            ApiError::PackageConflict => "package_conflict",
+            ApiError::MissingPackageFile => "missing_package_file",
+            ApiError::InvalidMultipart(_) => "invalid_multipart",
+            ApiError::NotADebianPackage => "not_a_debian_package",
+            ApiError::InvalidRequestBody(_) => "invalid_request_body",
+            ApiError::RequestBodyTooLarge => "request_body_too_large",
+            ApiError::InvalidQuery(_) => "invalid_query",
+            ApiError::InvalidDistribution(_) => "invalid_distribution",
+            ApiError::DistributionExists(_) => "distribution_exists",
+            ApiError::DistributionNotFound(_) => "distribution_not_found",
+            ApiError::LastDistribution(_) => "last_distribution",
+            ApiError::Unauthorized => "unauthorized",
+            ApiError::Io(_) => "io_error",
+        }
+    }
+}
+
+impl fmt::Display for ApiError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            ApiError::InvalidPath => write!(f, "Invalid path"),
+            ApiError::InvalidPackageFilename => {
+                write!(f, "Package filename must look like <name>_<version>_<architecture>.deb")
+            }
+            ApiError::PackageConflict => write!(f, "A different file with this name already exists"),
+            ApiError::MissingPackageFile => {
+                write!(f, "Upload needs a package name in the path or a multipart `file` field with a filename")
+            }
+            ApiError::InvalidMultipart(reason) => write!(f, "Invalid multipart body: {}", reason),
+            ApiError::NotADebianPackage => write!(f, "Upload is not a Debian package"),
+            ApiError::InvalidRequestBody(reason) => write!(f, "Invalid request body: {}", reason),
+            ApiError::RequestBodyTooLarge => write!(f, "Request body is too large"),
+            ApiError::InvalidQuery(reason) => write!(f, "Invalid query string: {}", reason),
+            ApiError::InvalidDistribution(problems) => write!(f, "Invalid distribution: {}", problems.join("; ")),
+            ApiError::DistributionExists(name) => write!(f, "Distribution `{}` already exists", name),
+            ApiError::DistributionNotFound(name) => write!(f, "Distribution `{}` does not exist", name),
+            ApiError::LastDistribution(name) => write!(f, "Distribution `{}` is the only one left", name),
+            ApiError::Unauthorized => write!(f, "Missing or invalid API token"),
+            ApiError::Io(err) => write!(f, "{}", err),
+        }
+    }
+}
+
+impl From<io::Error> for ApiError {
+    fn from(err: io::Error) -> Self {
+        ApiError::Io(err)
+    }
+}
+
+impl IntoResponse for ApiError {
+    fn into_response(self) -> Response {
+        let body = Json(json!({"error": self.to_string(), "code": self.code()}));
+        let mut response = (self.status(), body).into_response();
+        // bearer token challenges must say which scheme is expected (RFC 6750)
+        if let ApiError::Unauthorized = self {
+            response.headers_mut().insert(header::WWW_AUTHENTICATE, HeaderValue::from_static("Bearer"));
+        }
+        response
+    }
+}
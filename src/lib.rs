@@ -0,0 +1,714 @@
+use axum::{
+    body::Body,
+    extract::{DefaultBodyLimit, FromRef},
+    http::Request,
+    middleware,
+    routing::delete,
+    routing::get,
+    routing::post,
+    Router
+};
+use axum_server::tls_rustls::RustlsConfig;
+use metrics_exporter_prometheus::PrometheusHandle;
+use tower_http::{
+    request_id::{MakeRequestUuid, PropagateRequestIdLayer, SetRequestIdLayer},
+    trace::TraceLayer,
+};
+use tracing::{error, info};
+
+mod auth;
+mod error;
+mod metrics;
+mod package;
+mod repository;
+pub mod version;
+
+use std::error::Error;
+use std::sync::Arc;
+
+#[derive(Clone)]
+struct AppState {
+    archive: Arc<repository::DebianArchive>,
+    metrics: PrometheusHandle,
+}
+
+impl FromRef<AppState> for Arc<repository::DebianArchive> {
+    fn from_ref(state: &AppState) -> Self {
+        state.archive.clone()
+    }
+}
+
+impl FromRef<AppState> for PrometheusHandle {
+    fn from_ref(state: &AppState) -> Self {
+        state.metrics.clone()
+    }
+}
+
+// loads the PEM encoded certificate chain and key, naming the offending file on failure
+async fn load_tls_config(tls: &repository::TlsConfig) -> Result<RustlsConfig, String> {
+    let cert = tokio::fs::read(&tls.cert)
+        .await
+        .map_err(|err| format!("Could not read TLS certificate {}: {}", tls.cert, err))?;
+    let key = tokio::fs::read(&tls.key)
+        .await
+        .map_err(|err| format!("Could not read TLS key {}: {}", tls.key, err))?;
+    RustlsConfig::from_pem(cert, key)
+        .await
+        .map_err(|err| format!("Could not parse TLS certificate {} and key {} as PEM: {}", tls.cert, tls.key, err))
+}
+
+// resolves on Ctrl-C or SIGTERM so the server can stop accepting connections and let
+// in-flight uploads finish
+async fn shutdown_signal() {
+    // a handler that can't be installed never fires, the other one can still stop the server
+    let ctrl_c = async {
+        if let Err(err) = tokio::signal::ctrl_c().await {
+            error!("Could not install Ctrl-C handler: {}", err);
+            std::future::pending::<()>().await;
+        }
+    };
+
+    #[cfg(unix)]
+    let terminate = async {
+        match tokio::signal::unix::signal(tokio::signal::unix::SignalKind::terminate()) {
+            Ok(mut terminate) => {
+                terminate.recv().await;
+            }
+            Err(err) => {
+                error!("Could not install SIGTERM handler: {}", err);
+                std::future::pending::<()>().await;
+            }
+        }
+    };
+    #[cfg(not(unix))]
+    let terminate = std::future::pending::<()>();
+
+    tokio::select! {
+        _ = ctrl_c => {},
+        _ = terminate => {},
+    }
+    info!("shutting down, waiting for in-flight requests");
+}
+
+/// Builds the router serving the JSON API, `/pool/` and `/metrics` for the given archive.
+fn app(archive: Arc<repository::DebianArchive>, metrics: PrometheusHandle) -> Router {
+    let state = AppState { archive: archive.clone(), metrics };
+
+    // packages are streamed to disk, the default 2MB limit only gets in the way of multipart
+    let upload_routes = Router::new()
+        .route("/v1/packages/upload", post(package::upload_package))
+        .route("/v1/packages/upload/:package_name", post(package::upload_package))
+        .route("/v1/packages/upload-batch", post(package::upload_package_batch))
+        .layer(DefaultBodyLimit::disable());
+
+    // GET routes, including /pool/ for apt, stay open; everything that writes needs a token.
+    // JSON bodies are buffered in memory, so those routes keep the default limit
+    let write_routes = Router::new()
+        .merge(upload_routes)
+        .route("/v1/distributions", post(repository::handle_add_distribution))
+        .route("/v1/distributions/:name", delete(repository::handle_remove_distribution))
+        .route_layer(middleware::from_fn_with_state(archive, auth::require_api_token));
+
+    Router::new().route("/v1/packages", get(package::get_packages))
+                 .merge(write_routes)
+                 .route("/pool/*path", get(package::download_package))
+                 .route("/v1/repositories", get(repository::handle_get_repositories))
+                 .route("/v1/suites", get(repository::handle_get_suites))
+                 .route("/metrics", get(crate::metrics::handle_get_metrics)).with_state(state)
+                 // every request gets an x-request-id, echoed back and attached to its span
+                 .layer(PropagateRequestIdLayer::x_request_id())
+                 .layer(TraceLayer::new_for_http().make_span_with(|request: &Request<Body>| {
+                     let request_id = request
+                         .headers()
+                         .get("x-request-id")
+                         .and_then(|id| id.to_str().ok())
+                         .unwrap_or_default();
+                     tracing::info_span!("request", request_id, method = %request.method(), uri = %request.uri())
+                 }))
+                 .layer(SetRequestIdLayer::x_request_id(MakeRequestUuid))
+}
+
+/// Loads the configuration at `config_path` and serves the repository on `listen` until
+/// Ctrl-C or SIGTERM. Configuration and startup problems are returned for the caller to report.
+pub async fn run_server(config_path: &str, listen: &str) -> Result<(), Box<dyn Error>> {
+    let archive = repository::DebianArchive::new(config_path)?;
+    let tls = match &archive.tls {
+        Some(tls) => Some(load_tls_config(tls).await?),
+        None => None,
+    };
+
+    package::create_uploads_directory()
+        .await
+        .map_err(|err| format!("Could not create uploads directory: {}", err))?;
+
+    // both branches bind the same way so `localhost:3000` works with and without TLS
+    let listener = tokio::net::TcpListener::bind(listen)
+        .await
+        .map_err(|err| format!("Could not listen on {}: {}", listen, err))?;
+
+    // only once the port is ours, an instance started by mistake next to a running one must
+    // not delete that one's in-flight uploads
+    let removed = package::remove_partial_uploads()
+        .await
+        .map_err(|err| format!("Could not clean up partial uploads: {}", err))?;
+    if removed > 0 {
+        info!(removed, "removed partial uploads left over from a previous run");
+    }
+
+    let app = app(Arc::new(archive), crate::metrics::install_recorder()?);
+    Ok(serve(app, listener, tls).await?)
+}
+
+// serves `app` on `listener`, over TLS when a configuration is given, until Ctrl-C or SIGTERM
+async fn serve(app: Router, listener: tokio::net::TcpListener, tls: Option<RustlsConfig>) -> std::io::Result<()> {
+    let address = listener.local_addr()?;
+    match tls {
+        Some(config) => {
+            let handle = axum_server::Handle::new();
+            tokio::spawn({
+                let handle = handle.clone();
+                async move {
+                    shutdown_signal().await;
+                    handle.graceful_shutdown(None);
+                }
+            });
+            info!(%address, "listening with TLS");
+            axum_server::from_tcp_rustls(listener.into_std()?, config)
+                .handle(handle)
+                .serve(app.into_make_service())
+                .await
+        }
+        None => {
+            info!(%address, "listening");
+            axum::serve(listener, app)
+                .with_graceful_shutdown(shutdown_signal())
+                .await
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use axum::http::StatusCode;
+    use metrics_exporter_prometheus::PrometheusBuilder;
+    use std::sync::atomic::{AtomicU64, Ordering};
+    use tower::ServiceExt;
+
+    pub(crate) const CONFIG: &str = "distributions:\n  stable:\n    components: [main]\n    architectures: [amd64]\n";
+
+    // writes `config` to its own file in the temp directory so runtime changes don't collide
+    pub(crate) fn config_file(config: &str) -> String {
+        static CONFIGS: AtomicU64 = AtomicU64::new(0);
+        let id = CONFIGS.fetch_add(1, Ordering::Relaxed);
+        let path = std::env::temp_dir().join(format!("zorro-test-{}-{}.yaml", std::process::id(), id));
+        std::fs::write(&path, config).unwrap();
+        path.to_str().unwrap().to_owned()
+    }
+
+    pub(crate) fn archive(config: &str) -> Arc<repository::DebianArchive> {
+        Arc::new(repository::DebianArchive::new(&config_file(config)).unwrap())
+    }
+
+    pub(crate) async fn test_app(config: &str) -> Router {
+        package::create_uploads_directory().await.unwrap();
+        app(archive(config), PrometheusBuilder::new().build_recorder().handle())
+    }
+
+    // an ar archive with the members dpkg expects, `data` stands in for the data.tar.xz contents
+    pub(crate) fn debian_package(data: &[u8]) -> Vec<u8> {
+        let mut package = b"!<arch>\n".to_vec();
+        for (name, contents) in [("debian-binary", &b"2.0\n"[..]), ("control.tar.xz", b"control"), ("data.tar.xz", data)] {
+            let header = format!("{:<16}{:<12}{:<6}{:<6}{:<8}{:<10}`\n", name, 0, 0, 0, 100644, contents.len());
+            package.extend(header.as_bytes());
+            package.extend(contents);
+            if contents.len() % 2 == 1 {
+                package.push(b'\n');
+            }
+        }
+        package
+    }
+
+    pub(crate) async fn send(app: &Router, request: Request<Body>) -> (StatusCode, serde_json::Value) {
+        let response = app.clone().oneshot(request).await.unwrap();
+        let status = response.status();
+        let body = axum::body::to_bytes(response.into_body(), usize::MAX).await.unwrap();
+        (status, serde_json::from_slice(&body).unwrap_or_default())
+    }
+
+    // for routes that don't answer in JSON
+    pub(crate) async fn fetch(app: &Router, uri: &str) -> (StatusCode, axum::http::HeaderMap, Vec<u8>) {
+        let response = app.clone().oneshot(Request::get(uri).body(Body::empty()).unwrap()).await.unwrap();
+        let (status, headers) = (response.status(), response.headers().clone());
+        let body = axum::body::to_bytes(response.into_body(), usize::MAX).await.unwrap();
+        (status, headers, body.to_vec())
+    }
+
+    pub(crate) fn upload(uri: &str, body: Vec<u8>) -> Request<Body> {
+        Request::post(uri).body(Body::from(body)).unwrap()
+    }
+
+    // a multipart/form-data body with one part per (field name, filename, contents)
+    pub(crate) fn multipart_body(parts: &[(&str, Option<&str>, &[u8])]) -> Vec<u8> {
+        let mut body = Vec::new();
+        for (name, filename, contents) in parts {
+            body.extend(b"--zorro-boundary\r\n");
+            match filename {
+                Some(filename) => body.extend(
+                    format!("Content-Disposition: form-data; name=\"{}\"; filename=\"{}\"\r\n", name, filename).as_bytes(),
+                ),
+                None => body.extend(format!("Content-Disposition: form-data; name=\"{}\"\r\n", name).as_bytes()),
+            }
+            body.extend(b"\r\n");
+            body.extend(*contents);
+            body.extend(b"\r\n");
+        }
+        body.extend(b"--zorro-boundary--\r\n");
+        body
+    }
+
+    pub(crate) fn multipart_request(uri: &str, body: Vec<u8>) -> Request<Body> {
+        Request::post(uri)
+            .header("content-type", "multipart/form-data; boundary=zorro-boundary")
+            .body(Body::from(body))
+            .unwrap()
+    }
+
+    pub(crate) fn multipart_upload(uri: &str, parts: &[(&str, Option<&str>, &[u8])]) -> Request<Body> {
+        multipart_request(uri, multipart_body(parts))
+    }
+
+    #[tokio::test(flavor = "multi_thread", worker_threads = 4)]
+    async fn concurrent_rejecting_uploads_of_different_packages_conflict() {
+        let app = test_app(CONFIG).await;
+        let uri = "/v1/packages/upload/concurrent-conflict_1.0_amd64.deb?on_conflict=reject";
+
+        let uploads: Vec<_> = (0..8)
+            .map(|i| {
+                let (app, request) = (app.clone(), upload(uri, debian_package(format!("package {}", i).as_bytes())));
+                tokio::spawn(async move { send(&app, request).await })
+            })
+            .collect();
+        let mut responses = Vec::new();
+        for upload in uploads {
+            responses.push(upload.await.unwrap());
+        }
+        let _ = std::fs::remove_file("uploads/concurrent-conflict_1.0_amd64.deb");
+
+        let accepted = responses.iter().filter(|(status, _)| *status == StatusCode::OK).count();
+        assert_eq!(accepted, 1);
+        for (status, body) in responses.iter().filter(|(status, _)| *status != StatusCode::OK) {
+            assert_eq!(*status, StatusCode::CONFLICT);
+            assert_eq!(body["code"], "package_conflict");
+        }
+    }
+
+    #[tokio::test]
+    async fn identical_rejecting_upload_is_accepted() {
+        let app = test_app(CONFIG).await;
+        let uri = "/v1/packages/upload/identical-reupload_1.0_amd64.deb?on_conflict=reject";
+
+        let first = send(&app, upload(uri, debian_package(b"same"))).await;
+        let second = send(&app, upload(uri, debian_package(b"same"))).await;
+        let _ = std::fs::remove_file("uploads/identical-reupload_1.0_amd64.deb");
+
+        assert_eq!(first.0, StatusCode::OK);
+        assert_eq!(second.0, StatusCode::OK);
+    }
+
+    #[tokio::test]
+    async fn serves_the_api_over_tls() {
+        use tokio::io::{AsyncReadExt, AsyncWriteExt};
+
+        // a self-signed certificate for localhost, valid until 2126
+        let tls = repository::TlsConfig {
+            cert: "tests/fixtures/localhost.pem".to_owned(),
+            key: "tests/fixtures/localhost.key".to_owned(),
+        };
+        let config = load_tls_config(&tls).await.unwrap();
+        let listener = tokio::net::TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let address = listener.local_addr().unwrap();
+        tokio::spawn(serve(test_app(CONFIG).await, listener, Some(config)));
+
+        let mut roots = tokio_rustls::rustls::RootCertStore::empty();
+        let certificate = std::fs::read(&tls.cert).unwrap();
+        for certificate in rustls_pemfile::certs(&mut certificate.as_slice()) {
+            roots.add(certificate.unwrap()).unwrap();
+        }
+        let client = tokio_rustls::rustls::ClientConfig::builder().with_root_certificates(roots).with_no_client_auth();
+        let connector = tokio_rustls::TlsConnector::from(Arc::new(client));
+        let stream = tokio::net::TcpStream::connect(address).await.unwrap();
+        let mut stream = connector.connect("localhost".try_into().unwrap(), stream).await.unwrap();
+
+        stream
+            .write_all(b"GET /v1/repositories HTTP/1.1\r\nHost: localhost\r\nConnection: close\r\n\r\n")
+            .await
+            .unwrap();
+        let mut response = String::new();
+        stream.read_to_string(&mut response).await.unwrap();
+
+        assert!(response.starts_with("HTTP/1.1 200"), "{}", response);
+        assert!(response.ends_with(r#"{"stable":{"components":["main"],"architectures":["amd64"]}}"#), "{}", response);
+    }
+
+    #[tokio::test]
+    async fn invalid_upload_query_is_a_json_error() {
+        let app = test_app(CONFIG).await;
+        let (status, body) =
+            send(&app, upload("/v1/packages/upload/query_1.0_amd64.deb?on_conflict=bogus", debian_package(b""))).await;
+
+        assert_eq!(status, StatusCode::BAD_REQUEST);
+        assert_eq!(body["code"], "invalid_query");
+    }
+
+    #[tokio::test]
+    async fn uploads_need_a_configured_token() {
+        let app = test_app(&format!("{}api_tokens: [secret]\n", CONFIG)).await;
+        let uri = "/v1/packages/upload/authenticated_1.0_amd64.deb";
+        let with_token = |token: &str| {
+            let mut request = upload(uri, debian_package(b"authenticated"));
+            request.headers_mut().insert("authorization", format!("Bearer {}", token).parse().unwrap());
+            request
+        };
+
+        let missing = app.clone().oneshot(upload(uri, debian_package(b"authenticated"))).await.unwrap();
+        assert_eq!(missing.status(), StatusCode::UNAUTHORIZED);
+        assert_eq!(missing.headers()["www-authenticate"], "Bearer");
+        let body = axum::body::to_bytes(missing.into_body(), usize::MAX).await.unwrap();
+        assert_eq!(serde_json::from_slice::<serde_json::Value>(&body).unwrap()["code"], "unauthorized");
+        let (wrong, _) = send(&app, with_token("guess")).await;
+        assert_eq!(wrong, StatusCode::UNAUTHORIZED);
+        assert!(!std::path::Path::new("uploads/authenticated_1.0_amd64.deb").exists());
+
+        let (authorized, _) = send(&app, with_token("secret")).await;
+        let _ = std::fs::remove_file("uploads/authenticated_1.0_amd64.deb");
+        assert_eq!(authorized, StatusCode::OK);
+    }
+
+    #[tokio::test]
+    async fn reads_stay_open_when_tokens_are_configured() {
+        let app = test_app(&format!("{}api_tokens: [secret]\n", CONFIG)).await;
+        let (status, _) = send(&app, Request::get("/v1/repositories").body(Body::empty()).unwrap()).await;
+        assert_eq!(status, StatusCode::OK);
+    }
+
+    #[tokio::test]
+    async fn raw_upload_is_stored_under_the_path_name() {
+        let app = test_app(CONFIG).await;
+        let package = debian_package(b"raw");
+
+        let (status, _) = send(&app, upload("/v1/packages/upload/raw-upload_1.0_amd64.deb", package.clone())).await;
+        let stored = std::fs::read("uploads/raw-upload_1.0_amd64.deb");
+        let _ = std::fs::remove_file("uploads/raw-upload_1.0_amd64.deb");
+
+        assert_eq!(status, StatusCode::OK);
+        assert_eq!(stored.unwrap(), package);
+    }
+
+    #[tokio::test]
+    async fn raw_upload_needs_a_path_name() {
+        let app = test_app(CONFIG).await;
+        let (status, body) = send(&app, upload("/v1/packages/upload", debian_package(b"raw"))).await;
+
+        assert_eq!(status, StatusCode::BAD_REQUEST);
+        assert_eq!(body["code"], "missing_package_file");
+    }
+
+    #[tokio::test]
+    async fn multipart_upload_is_stored_under_its_filename() {
+        let app = test_app(CONFIG).await;
+        let package = debian_package(b"multipart");
+        let parts: &[(&str, Option<&str>, &[u8])] =
+            &[("comment", None, b"ignored"), ("file", Some("multipart-upload_1.0_amd64.deb"), &package)];
+
+        let (status, _) = send(&app, multipart_upload("/v1/packages/upload", parts)).await;
+        let stored = std::fs::read("uploads/multipart-upload_1.0_amd64.deb");
+        let _ = std::fs::remove_file("uploads/multipart-upload_1.0_amd64.deb");
+
+        assert_eq!(status, StatusCode::OK);
+        assert_eq!(stored.unwrap(), package);
+    }
+
+    #[tokio::test]
+    async fn multipart_upload_prefers_the_path_name() {
+        let app = test_app(CONFIG).await;
+        let package = debian_package(b"named");
+        let parts: &[(&str, Option<&str>, &[u8])] = &[("file", Some("package.deb"), &package)];
+
+        let (status, _) = send(&app, multipart_upload("/v1/packages/upload/path-named_1.0_amd64.deb", parts)).await;
+        let stored = std::fs::read("uploads/path-named_1.0_amd64.deb");
+        let _ = std::fs::remove_file("uploads/path-named_1.0_amd64.deb");
+
+        assert_eq!(status, StatusCode::OK);
+        assert_eq!(stored.unwrap(), package);
+    }
+
+    #[tokio::test]
+    async fn multipart_upload_without_a_file_field_is_rejected() {
+        let app = test_app(CONFIG).await;
+        let package = debian_package(b"misnamed");
+        let parts: &[(&str, Option<&str>, &[u8])] = &[("package", Some("misnamed_1.0_amd64.deb"), &package)];
+
+        let (status, body) = send(&app, multipart_upload("/v1/packages/upload", parts)).await;
+
+        assert_eq!(status, StatusCode::BAD_REQUEST);
+        assert_eq!(body["code"], "missing_package_file");
+        assert!(!std::path::Path::new("uploads/misnamed_1.0_amd64.deb").exists());
+    }
+
+    #[tokio::test]
+    async fn run_server_returns_startup_errors() {
+        let err = run_server("tests/fixtures/missing.yaml", "127.0.0.1:0").await.unwrap_err();
+        assert!(err.to_string().starts_with("Could not read configuration tests/fixtures/missing.yaml"), "{}", err);
+
+        let err = run_server(&config_file(CONFIG), "localhost:abc").await.unwrap_err();
+        assert!(err.to_string().starts_with("Could not listen on localhost:abc"), "{}", err);
+    }
+
+    fn add_distribution(body: &str) -> Request<Body> {
+        Request::post("/v1/distributions")
+            .header("content-type", "application/json")
+            .body(Body::from(body.to_owned()))
+            .unwrap()
+    }
+
+    #[tokio::test]
+    async fn distribution_names_must_be_safe() {
+        let app = test_app(CONFIG).await;
+        let (status, body) =
+            send(&app, add_distribution(r#"{"name": "../../etc", "components": ["main"], "architectures": ["amd64"]}"#)).await;
+        assert_eq!(status, StatusCode::BAD_REQUEST);
+        assert_eq!(body["code"], "invalid_distribution");
+
+        let (_, suites) = send(&app, Request::get("/v1/suites").body(Body::empty()).unwrap()).await;
+        assert_eq!(suites.as_array().unwrap().len(), 1);
+    }
+
+    #[tokio::test]
+    async fn distribution_names_are_trimmed() {
+        let app = test_app(CONFIG).await;
+        let (status, body) =
+            send(&app, add_distribution(r#"{"name": " testing ", "components": ["main"], "architectures": ["amd64"]}"#)).await;
+        assert_eq!(status, StatusCode::CREATED);
+        assert_eq!(body["name"], "testing");
+
+        let (status, _) = send(&app, Request::delete("/v1/distributions/testing").body(Body::empty()).unwrap()).await;
+        assert_eq!(status, StatusCode::OK);
+    }
+
+    #[tokio::test]
+    async fn only_uploads_lift_the_body_limit() {
+        let app = test_app(CONFIG).await;
+        let large = vec![b' '; 3 * 1024 * 1024];
+
+        let (status, _) = send(&app, upload("/v1/packages/upload/large-upload_1.0_amd64.deb", debian_package(&large))).await;
+        let _ = std::fs::remove_file("uploads/large-upload_1.0_amd64.deb");
+        assert_eq!(status, StatusCode::OK);
+
+        let body = format!(r#"{{"name": "large", "components": ["main"], "architectures": ["amd64"]}}{}"#, " ".repeat(large.len()));
+        let (status, body) = send(&app, add_distribution(&body)).await;
+        assert_eq!(status, StatusCode::PAYLOAD_TOO_LARGE);
+        assert_eq!(body["code"], "request_body_too_large");
+    }
+    #[tokio::test]
+    async fn batch_upload_of_good_packages_succeeds() {
+        let app = test_app(CONFIG).await;
+        let (first, second) = (debian_package(b"first"), debian_package(b"second"));
+        let parts: &[(&str, Option<&str>, &[u8])] =
+            &[("a", Some("batch-first_1.0_amd64.deb"), &first), ("b", Some("batch-second_1.0_amd64.deb"), &second)];
+
+        let (status, body) = send(&app, multipart_upload("/v1/packages/upload-batch", parts)).await;
+        let stored = (std::fs::read("uploads/batch-first_1.0_amd64.deb"), std::fs::read("uploads/batch-second_1.0_amd64.deb"));
+        let _ = std::fs::remove_file("uploads/batch-first_1.0_amd64.deb");
+        let _ = std::fs::remove_file("uploads/batch-second_1.0_amd64.deb");
+
+        assert_eq!(status, StatusCode::OK);
+        assert_eq!(
+            body,
+            serde_json::json!([
+                {"filename": "batch-first_1.0_amd64.deb", "status": 200},
+                {"filename": "batch-second_1.0_amd64.deb", "status": 200},
+            ])
+        );
+        assert_eq!(stored.0.unwrap(), first);
+        assert_eq!(stored.1.unwrap(), second);
+    }
+
+    #[tokio::test]
+    async fn batch_upload_reports_each_failure() {
+        let app = test_app(CONFIG).await;
+        let package = debian_package(b"good");
+        let parts: &[(&str, Option<&str>, &[u8])] = &[
+            ("a", Some("batch-good_1.0_amd64.deb"), &package),
+            ("b", Some("Bad Name.deb"), &package),
+            ("c", Some("batch-also-good_1.0_amd64.deb"), &package),
+        ];
+
+        let (status, body) = send(&app, multipart_upload("/v1/packages/upload-batch", parts)).await;
+        let stored = ["uploads/batch-good_1.0_amd64.deb", "uploads/batch-also-good_1.0_amd64.deb"]
+            .map(|path| std::fs::remove_file(path).is_ok());
+
+        assert_eq!(status, StatusCode::MULTI_STATUS);
+        let statuses: Vec<_> = body.as_array().unwrap().iter().map(|result| result["status"].clone()).collect();
+        assert_eq!(statuses, [200, 400, 200]);
+        assert_eq!(body[1]["filename"], "Bad Name.deb");
+        assert_eq!(body[1]["code"], "invalid_package_filename");
+        assert_eq!(stored, [true, true]);
+    }
+
+    #[tokio::test]
+    async fn batch_upload_keeps_results_before_a_malformed_part() {
+        let app = test_app(CONFIG).await;
+        let package = debian_package(b"before");
+        let mut body = multipart_body(&[("a", Some("batch-before_1.0_amd64.deb"), &package)]);
+        // replace the closing boundary with a part whose headers never end
+        body.truncate(body.len() - b"--zorro-boundary--\r\n".len());
+        body.extend(b"--zorro-boundary\r\nContent-Disposition: form-");
+
+        let (status, body) = send(&app, multipart_request("/v1/packages/upload-batch", body)).await;
+        let stored = std::fs::remove_file("uploads/batch-before_1.0_amd64.deb").is_ok();
+
+        assert_eq!(status, StatusCode::MULTI_STATUS);
+        assert_eq!(body.as_array().unwrap().len(), 2);
+        assert_eq!(body[0]["status"], 200);
+        assert_eq!(body[1]["code"], "invalid_multipart");
+        assert!(stored);
+    }
+
+    #[tokio::test]
+    async fn batch_upload_needs_named_parts() {
+        let app = test_app(CONFIG).await;
+        let package = debian_package(b"unnamed");
+        let parts: &[(&str, Option<&str>, &[u8])] = &[("a", None, &package), ("b", None, b"comment")];
+
+        let (status, body) = send(&app, multipart_upload("/v1/packages/upload-batch", parts)).await;
+
+        assert_eq!(status, StatusCode::BAD_REQUEST);
+        assert_eq!(body["code"], "missing_package_file");
+    }
+
+    #[tokio::test]
+    async fn registering_an_existing_distribution_conflicts() {
+        let app = test_app(CONFIG).await;
+        let (status, body) =
+            send(&app, add_distribution(r#"{"name": "stable", "components": ["contrib"], "architectures": ["arm64"]}"#)).await;
+
+        assert_eq!(status, StatusCode::CONFLICT);
+        assert_eq!(body["code"], "distribution_exists");
+        let (_, repositories) = send(&app, Request::get("/v1/repositories").body(Body::empty()).unwrap()).await;
+        assert_eq!(repositories["stable"]["components"], serde_json::json!(["main"]));
+    }
+
+    #[tokio::test]
+    async fn registered_distributions_survive_a_restart() {
+        package::create_uploads_directory().await.unwrap();
+        let path = config_file(CONFIG);
+        let archive = Arc::new(repository::DebianArchive::new(&path).unwrap());
+        let app = app(archive, PrometheusBuilder::new().build_recorder().handle());
+
+        let (status, _) =
+            send(&app, add_distribution(r#"{"name": "testing", "components": ["main"], "architectures": ["amd64"]}"#)).await;
+        assert_eq!(status, StatusCode::CREATED);
+
+        let restarted = repository::DebianArchive::new(&path).unwrap();
+        let distributions = restarted.distributions();
+        assert_eq!(distributions.keys().collect::<Vec<_>>(), ["stable", "testing"]);
+        assert_eq!(distributions["testing"].architectures, ["amd64"]);
+    }
+
+    #[tokio::test]
+    async fn pool_streams_stored_packages_back() {
+        let app = test_app(CONFIG).await;
+        let package = debian_package(b"download");
+        let (status, _) = send(&app, upload("/v1/packages/upload/pool-download_1.0_amd64.deb", package.clone())).await;
+        assert_eq!(status, StatusCode::OK);
+
+        let (status, headers, body) = fetch(&app, "/pool/pool-download_1.0_amd64.deb").await;
+        let _ = std::fs::remove_file("uploads/pool-download_1.0_amd64.deb");
+
+        assert_eq!(status, StatusCode::OK);
+        assert_eq!(headers["content-type"], "application/vnd.debian.binary-package");
+        assert_eq!(headers["content-length"], package.len().to_string().as_str());
+        assert_eq!(body, package);
+    }
+
+    #[tokio::test]
+    async fn pool_rejects_traversal_in_plain_text() {
+        let app = test_app(CONFIG).await;
+        let (status, headers, body) = fetch(&app, "/pool/..%2Fx").await;
+
+        assert_eq!(status, StatusCode::BAD_REQUEST);
+        assert!(headers["content-type"].to_str().unwrap().starts_with("text/plain"));
+        assert_eq!(body, b"Invalid path");
+    }
+
+    #[tokio::test]
+    async fn pool_answers_missing_packages_in_plain_text() {
+        let app = test_app(CONFIG).await;
+        let (status, headers, body) = fetch(&app, "/pool/pool-missing_1.0_amd64.deb").await;
+
+        assert_eq!(status, StatusCode::NOT_FOUND);
+        assert!(headers["content-type"].to_str().unwrap().starts_with("text/plain"));
+        assert!(serde_json::from_slice::<serde_json::Value>(&body).is_err());
+    }
+
+    #[tokio::test]
+    async fn pool_never_serves_partial_uploads() {
+        let app = test_app(CONFIG).await;
+        std::fs::write("uploads/.pool-partial_1.0_amd64.deb.0.part", debian_package(b"partial")).unwrap();
+
+        let (status, _, _) = fetch(&app, "/pool/.pool-partial_1.0_amd64.deb.0.part").await;
+        let _ = std::fs::remove_file("uploads/.pool-partial_1.0_amd64.deb.0.part");
+
+        assert_eq!(status, StatusCode::NOT_FOUND);
+    }
+
+    #[test]
+    fn metrics_count_uploads_and_failures() {
+        // the recorder is local to this thread, so the runtime driving the requests must be too
+        let recorder = PrometheusBuilder::new().build_recorder();
+        let handle = recorder.handle();
+        let runtime = tokio::runtime::Builder::new_current_thread().enable_all().build().unwrap();
+
+        let metrics = ::metrics::with_local_recorder(&recorder, || {
+            runtime.block_on(async {
+                package::create_uploads_directory().await.unwrap();
+                let app = app(archive(CONFIG), handle);
+                let package = debian_package(b"counted");
+
+                let (status, _) = send(&app, upload("/v1/packages/upload/metrics-counted_1.0_amd64.deb", package.clone())).await;
+                let _ = std::fs::remove_file("uploads/metrics-counted_1.0_amd64.deb");
+                assert_eq!(status, StatusCode::OK);
+                let uri = "/v1/packages/upload/metrics-query_1.0_amd64.deb?on_conflict=bogus";
+                let (status, _) = send(&app, upload(uri, package.clone())).await;
+                assert_eq!(status, StatusCode::BAD_REQUEST);
+                let (status, _) = send(&app, multipart_request("/v1/packages/upload-batch", b"not multipart".to_vec())).await;
+                assert_eq!(status, StatusCode::BAD_REQUEST);
+
+                let (status, _, body) = fetch(&app, "/metrics").await;
+                assert_eq!(status, StatusCode::OK);
+                String::from_utf8(body).unwrap()
+            })
+        });
+
+        assert!(metrics.contains("zorro_uploads_total 3"), "{}", metrics);
+        assert!(metrics.contains(r#"zorro_upload_errors_total{reason="invalid_query"} 1"#), "{}", metrics);
+        assert!(metrics.contains(r#"zorro_upload_errors_total{reason="invalid_multipart"} 1"#), "{}", metrics);
+    }
+
+    #[tokio::test]
+    async fn bogus_upload_is_rejected_without_leaving_files() {
+        let app = test_app(CONFIG).await;
+        let (status, body) =
+            send(&app, upload("/v1/packages/upload/bogus_1.0_amd64.deb", b"not an ar archive".to_vec())).await;
+
+        assert_eq!(status, StatusCode::BAD_REQUEST);
+        assert_eq!(body["code"], "not_a_debian_package");
+        let left: Vec<_> = std::fs::read_dir("uploads")
+            .unwrap()
+            .map(|entry| entry.unwrap().file_name().to_string_lossy().into_owned())
+            .filter(|name| name.contains("bogus_1.0_amd64.deb"))
+            .collect();
+        assert!(left.is_empty(), "{:?}", left);
+    }
+}
@@ -1,30 +1,37 @@
-use axum::{
-    routing::get,
-    routing::post,
-    Router
-};
-
-mod package;
-mod repository;
-
-use std::sync::Arc;
+use clap::Parser;
+use tracing_subscriber::EnvFilter;
 
 const CONFIG_PATH: &str = ".config/repository_structure.yaml";
+const LISTEN_ADDRESS: &str = "127.0.0.1:3000";
 
-#[tokio::main]
-async fn main() {
-    let archive = repository::DebianArchive::new(CONFIG_PATH);
+#[derive(Parser)]
+#[command(version, about = "A Debian package repository server")]
+struct Args {
+    /// Path to the repository structure configuration
+    #[arg(long, env = "ZORRO_CONFIG", default_value = CONFIG_PATH)]
+    config: String,
 
-    let shared_archive = Arc::new(archive); 
+    /// Address to listen on
+    #[arg(long, env = "ZORRO_LISTEN", default_value = LISTEN_ADDRESS)]
+    listen: String,
+}
 
-    let app = Router::new().route("/v1/packages", get(package::get_packages))
-                           .route("/v1/packages/upload/:package_name", post(package::upload_package))
-                           .route("/v1/repositories", get(repository::handle_get_repositories)).with_state(shared_archive);
+// logs are human readable by default, set ZORRO_LOG_FORMAT=json for structured output
+fn init_tracing() {
+    let filter = EnvFilter::try_from_default_env().unwrap_or_else(|_| EnvFilter::new("info"));
+    let subscriber = tracing_subscriber::fmt().with_env_filter(filter);
+    match std::env::var("ZORRO_LOG_FORMAT").as_deref() {
+        Ok("json") => subscriber.json().init(),
+        _ => subscriber.pretty().init(),
+    }
+}
 
-    let listener = tokio::net::TcpListener::bind("127.0.0.1:3000")
-        .await
-        .unwrap();
-    println!("listening on {}", listener.local_addr().unwrap());
-    package::create_uploads_directory().await.expect("Could not create uploads directory");
-    axum::serve(listener, app).await.unwrap();
+#[tokio::main]
+async fn main() {
+    let args = Args::parse();
+    init_tracing();
+    if let Err(err) = zorro::run_server(&args.config, &args.listen).await {
+        tracing::error!("{}", err);
+        std::process::exit(1);
+    }
 }
@@ -0,0 +1,19 @@
+use axum::extract::State;
+use metrics_exporter_prometheus::{BuildError, PrometheusBuilder, PrometheusHandle};
+
+pub const UPLOADS_TOTAL: &str = "zorro_uploads_total";
+pub const UPLOAD_ERRORS_TOTAL: &str = "zorro_upload_errors_total";
+
+/// Installs the global Prometheus recorder, the returned handle renders the current values.
+/// Fails when a recorder is already installed in this process.
+pub fn install_recorder() -> Result<PrometheusHandle, BuildError> {
+    let handle = PrometheusBuilder::new().install_recorder()?;
+
+    ::metrics::describe_counter!(UPLOADS_TOTAL, "Package uploads received");
+    ::metrics::describe_counter!(UPLOAD_ERRORS_TOTAL, "Package uploads that failed, by reason");
+    Ok(handle)
+}
+
+pub async fn handle_get_metrics(State(handle): State<PrometheusHandle>) -> String {
+    handle.render()
+}
@@ -1,15 +1,19 @@
 use axum::{
-    extract::{Path, Request},
-    body::Bytes,
-    http::StatusCode,
-    response::Json,
+    extract::{rejection::QueryRejection, FromRequest, Multipart, Path, Query, Request},
+    body::{Body, Bytes},
+    http::{header, StatusCode},
+    response::{IntoResponse, Json, Response},
     BoxError,
 };
 use serde::{Deserialize, Serialize};
 use futures::{Stream, TryStreamExt};
-use tokio_util::io::StreamReader;
-use tokio::{fs::File, io::BufWriter};
+use tokio_util::io::{ReaderStream, StreamReader};
+use tokio::{fs::File, io::{AsyncReadExt, AsyncSeekExt, BufWriter}};
 use std::io;
+use std::sync::atomic::{AtomicU64, Ordering};
+use tracing::{error, info, Instrument};
+
+use crate::error::ApiError;
 
 #[derive(Serialize, Deserialize)]
 pub struct Package {
@@ -20,44 +24,364 @@ pub struct Package {
 
 const UPLOADS_DIRECTORY: &str = "uploads";
 
+// uploads are streamed into a hidden file with this suffix and only renamed to their final
+// name once the whole body has arrived
+const PARTIAL_UPLOAD_SUFFIX: &str = ".part";
+
+static PARTIAL_UPLOAD_COUNTER: AtomicU64 = AtomicU64::new(0);
+
+/// What to do when an upload targets a file that already exists with different content.
+/// Re-uploading identical content is always accepted.
+#[derive(Deserialize, Default, Clone, Copy, PartialEq)]
+#[serde(rename_all = "lowercase")]
+pub enum OnConflict {
+    #[default]
+    Overwrite,
+    Reject,
+}
+
+#[derive(Deserialize)]
+pub struct UploadOptions {
+    #[serde(default)]
+    pub on_conflict: OnConflict,
+}
+
+/// Outcome of one file in a batch upload. A body that turns malformed partway through ends
+/// with an entry without a filename.
+#[derive(Serialize)]
+pub struct BatchUploadResult {
+    pub filename: String,
+    pub status: u16,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub error: Option<String>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub code: Option<&'static str>,
+}
+
 pub async fn create_uploads_directory() -> std::io::Result<()> {
     tokio::fs::create_dir_all(UPLOADS_DIRECTORY).await
 }
 
+// uploads interrupted by a crash or kill leave their partial file behind, finished packages
+// live in the same directory and are left alone
+pub async fn remove_partial_uploads() -> std::io::Result<usize> {
+    remove_partial_uploads_from(std::path::Path::new(UPLOADS_DIRECTORY)).await
+}
+
+async fn remove_partial_uploads_from(directory: &std::path::Path) -> std::io::Result<usize> {
+    let mut removed = 0;
+    let mut entries = tokio::fs::read_dir(directory).await?;
+    while let Some(entry) = entries.next_entry().await? {
+        let name = entry.file_name();
+        let name = name.to_string_lossy();
+        if name.starts_with('.') && name.ends_with(PARTIAL_UPLOAD_SUFFIX) {
+            tokio::fs::remove_file(entry.path()).await?;
+            removed += 1;
+        }
+    }
+    Ok(removed)
+}
+
+// accepts either a raw body, named by the path, or a multipart/form-data body whose `file` field
+// is named by its filename unless the path names it
 pub async fn upload_package(
-    Path(package_name): Path<String>,
+    package_name: Option<Path<String>>,
+    options: Result<Query<UploadOptions>, QueryRejection>,
     request: Request,
-) -> Result<(), (StatusCode, String)> {
-    stream_to_file(&package_name, request.into_body().into_data_stream()).await
+) -> Result<(), ApiError> {
+    let result = async {
+        let Query(options) = options.map_err(|rejection| ApiError::InvalidQuery(rejection.body_text()))?;
+        let package_name = package_name.map(|Path(package_name)| package_name);
+        if is_multipart(&request) {
+            receive_multipart_package(package_name, options, request).await
+        } else {
+            match package_name {
+                Some(package_name) => {
+                    receive_package(&package_name, request.into_body().into_data_stream(), options.on_conflict).await
+                }
+                None => Err(ApiError::MissingPackageFile),
+            }
+        }
+    }
+    .await;
+    record_upload(result.as_ref().err());
+    result
+}
+
+// stores every part of a multipart body that carries a filename, one failing package doesn't
+// stop the others
+pub async fn upload_package_batch(
+    options: Result<Query<UploadOptions>, QueryRejection>,
+    request: Request,
+) -> Result<(StatusCode, Json<Vec<BatchUploadResult>>), ApiError> {
+    // failures of the request as a whole are counted here, those of single parts as they happen
+    let response = async {
+        let Query(options) = options.map_err(|rejection| ApiError::InvalidQuery(rejection.body_text()))?;
+        let mut multipart = Multipart::from_request(request, &())
+            .await
+            .map_err(|rejection| ApiError::InvalidMultipart(rejection.body_text()))?;
+        let mut results = Vec::new();
+
+        loop {
+            let field = match multipart.next_field().await {
+                Ok(Some(field)) => field,
+                Ok(None) => break,
+                // the parts before a malformed one may already be stored, report them rather than
+                // failing the whole request
+                Err(err) if !results.is_empty() => {
+                    let err = ApiError::InvalidMultipart(err.body_text());
+                    record_upload(Some(&err));
+                    results.push(BatchUploadResult {
+                        filename: String::new(),
+                        status: err.status().as_u16(),
+                        error: Some(err.to_string()),
+                        code: Some(err.code()),
+                    });
+                    break;
+                }
+                Err(err) => return Err(ApiError::InvalidMultipart(err.body_text())),
+            };
+            let Some(filename) = field.file_name().map(str::to_owned) else {
+                continue;
+            };
+            let result = receive_package(&filename, field, options.on_conflict).await;
+            record_upload(result.as_ref().err());
+
+            results.push(match result {
+                Ok(()) => BatchUploadResult { filename, status: StatusCode::OK.as_u16(), error: None, code: None },
+                Err(err) => BatchUploadResult {
+                    filename,
+                    status: err.status().as_u16(),
+                    error: Some(err.to_string()),
+                    code: Some(err.code()),
+                },
+            });
+        }
+
+        if results.is_empty() {
+            return Err(ApiError::MissingPackageFile);
+        }
+        let status = if results.iter().all(|result| result.error.is_none()) {
+            StatusCode::OK
+        } else {
+            StatusCode::MULTI_STATUS
+        };
+        Ok((status, Json(results)))
+    }
+    .await;
+    if let Err(err) = &response {
+        record_upload(Some(err));
+    }
+    response
 }
 
-async fn stream_to_file<S, E>(path: &str, stream: S) -> Result<(), (StatusCode, String)>
+fn record_upload(error: Option<&ApiError>) {
+    metrics::counter!(crate::metrics::UPLOADS_TOTAL).increment(1);
+    if let Some(err) = error {
+        metrics::counter!(crate::metrics::UPLOAD_ERRORS_TOTAL, "reason" => err.code()).increment(1);
+    }
+}
+
+fn is_multipart(request: &Request) -> bool {
+    request
+        .headers()
+        .get(header::CONTENT_TYPE)
+        .and_then(|content_type| content_type.to_str().ok())
+        .is_some_and(|content_type| content_type.starts_with("multipart/form-data"))
+}
+
+async fn receive_multipart_package(
+    package_name: Option<String>,
+    options: UploadOptions,
+    request: Request,
+) -> Result<(), ApiError> {
+    let mut multipart = Multipart::from_request(request, &())
+        .await
+        .map_err(|rejection| ApiError::InvalidMultipart(rejection.body_text()))?;
+
+    while let Some(field) = multipart
+        .next_field()
+        .await
+        .map_err(|err| ApiError::InvalidMultipart(err.body_text()))?
+    {
+        if field.name() != Some("file") {
+            continue;
+        }
+        let package_name = match package_name.or_else(|| field.file_name().map(str::to_owned)) {
+            Some(package_name) => package_name,
+            None => return Err(ApiError::MissingPackageFile),
+        };
+        return receive_package(&package_name, field, options.on_conflict).await;
+    }
+
+    Err(ApiError::MissingPackageFile)
+}
+
+async fn receive_package<S, E>(package_name: &str, stream: S, on_conflict: OnConflict) -> Result<(), ApiError>
+where
+    S: Stream<Item = Result<Bytes, E>>,
+    E: Into<BoxError>,
+{
+    if !package_filename_is_valid(package_name) {
+        return Err(ApiError::InvalidPackageFilename);
+    }
+
+    // the filename was validated above, so it splits into name, version and architecture
+    let mut parts = package_name.split('_');
+    let span = tracing::info_span!("upload", package = parts.next(), version = parts.next());
+    stream_to_file(package_name, stream, on_conflict)
+        .instrument(span)
+        .await
+}
+
+async fn stream_to_file<S, E>(path: &str, stream: S, on_conflict: OnConflict) -> Result<(), ApiError>
 where
     S: Stream<Item = Result<Bytes, E>>,
     E: Into<BoxError>,
 {
     if !path_is_valid(path) {
-        return Err((StatusCode::BAD_REQUEST, "Invalid path".to_owned()));
+        return Err(ApiError::InvalidPath);
     }
-    println!("File arriving: {}", path);
+    info!(filename = path, "file arriving");
+
+    let destination = std::path::Path::new(UPLOADS_DIRECTORY).join(path);
+    let partial = partial_upload_path(path);
 
-    async {
+    let written = async {
         // Convert the stream into an `AsyncRead`.
-        let body_with_io_error = stream.map_err(|err| io::Error::new(io::ErrorKind::Other, err));
+        let body_with_io_error = stream.map_err(io::Error::other);
         let body_reader = StreamReader::new(body_with_io_error);
         futures::pin_mut!(body_reader);
 
         // Create the file. `File` implements `AsyncWrite`.
-        let path = std::path::Path::new(UPLOADS_DIRECTORY).join(path);
-        let mut file = BufWriter::new(File::create(path).await?);
+        let mut file = BufWriter::new(File::create(&partial).await?);
 
         // Copy the body into the file.
         tokio::io::copy(&mut body_reader, &mut file).await?;
 
         Ok::<_, io::Error>(())
     }
-    .await
-    .map_err(|err| (StatusCode::INTERNAL_SERVER_ERROR, err.to_string()))
+    .await;
+
+    let result = match written {
+        Ok(()) => match is_debian_package(&partial).await {
+            Ok(true) => finish_upload(&partial, &destination, on_conflict).await,
+            Ok(false) => Err(ApiError::NotADebianPackage),
+            Err(err) => Err(err.into()),
+        },
+        Err(err) => Err(err.into()),
+    };
+    if let Err(err) = &result {
+        error!(filename = path, code = err.code(), error = %err, "upload failed");
+        // the partial file may already be gone if it was renamed, so ignore failures here
+        let _ = tokio::fs::remove_file(&partial).await;
+    }
+    result
+}
+
+fn partial_upload_path(path: &str) -> std::path::PathBuf {
+    let id = PARTIAL_UPLOAD_COUNTER.fetch_add(1, Ordering::Relaxed);
+    std::path::Path::new(UPLOADS_DIRECTORY).join(format!(".{}.{}{}", path, id, PARTIAL_UPLOAD_SUFFIX))
+}
+
+// moves a completely received upload to its final name, honoring the conflict policy when the
+// destination already exists
+async fn finish_upload(
+    partial: &std::path::Path,
+    destination: &std::path::Path,
+    on_conflict: OnConflict,
+) -> Result<(), ApiError> {
+    if on_conflict == OnConflict::Overwrite {
+        return Ok(tokio::fs::rename(partial, destination).await?);
+    }
+
+    // linking fails when the destination exists, so of two concurrent uploads only one can
+    // claim the name and the other is compared against it
+    match tokio::fs::hard_link(partial, destination).await {
+        Ok(()) => {}
+        Err(err) if err.kind() == io::ErrorKind::AlreadyExists => {
+            if !files_are_identical(partial, destination).await? {
+                return Err(ApiError::PackageConflict);
+            }
+            // identical re-upload, keep the existing file untouched
+        }
+        Err(err) => return Err(err.into()),
+    }
+    Ok(tokio::fs::remove_file(partial).await?)
+}
+
+// a .deb is an ar archive holding (at least) a control.tar.* and a data.tar.* member, only the
+// member headers are read and their contents skipped
+async fn is_debian_package(path: &std::path::Path) -> io::Result<bool> {
+    const AR_MAGIC: &[u8; 8] = b"!<arch>\n";
+    const AR_HEADER_LENGTH: u64 = 60;
+
+    let mut file = File::open(path).await?;
+    let length = file.metadata().await?.len();
+
+    let mut magic = [0; 8];
+    if length < AR_MAGIC.len() as u64 {
+        return Ok(false);
+    }
+    file.read_exact(&mut magic).await?;
+    if &magic != AR_MAGIC {
+        return Ok(false);
+    }
+
+    let (mut has_control, mut has_data) = (false, false);
+    let mut offset = AR_MAGIC.len() as u64;
+    let mut header = [0; AR_HEADER_LENGTH as usize];
+    while offset < length {
+        // whatever follows the last member has to be another complete header
+        if offset + AR_HEADER_LENGTH > length {
+            return Ok(false);
+        }
+        file.seek(io::SeekFrom::Start(offset)).await?;
+        file.read_exact(&mut header).await?;
+        if &header[58..60] != b"`\n" {
+            return Ok(false);
+        }
+
+        let name = std::str::from_utf8(&header[..16]).unwrap_or_default().trim_end().trim_end_matches('/');
+        let Some(size) = std::str::from_utf8(&header[48..58])
+            .ok()
+            .and_then(|size| size.trim().parse::<u64>().ok())
+        else {
+            return Ok(false);
+        };
+        // a member claiming more data than the file holds was truncated
+        if offset + AR_HEADER_LENGTH + size > length {
+            return Ok(false);
+        }
+        has_control |= name.starts_with("control.tar");
+        has_data |= name.starts_with("data.tar");
+
+        // member data is padded to an even length
+        offset += AR_HEADER_LENGTH + size + size % 2;
+    }
+
+    Ok(has_control && has_data)
+}
+
+async fn files_are_identical(a: &std::path::Path, b: &std::path::Path) -> io::Result<bool> {
+    if tokio::fs::metadata(a).await?.len() != tokio::fs::metadata(b).await?.len() {
+        return Ok(false);
+    }
+
+    let mut file_a = File::open(a).await?;
+    let mut file_b = File::open(b).await?;
+    let mut buffer_a = vec![0; 64 * 1024];
+    let mut buffer_b = vec![0; 64 * 1024];
+    loop {
+        let read = file_a.read(&mut buffer_a).await?;
+        if read == 0 {
+            return Ok(true);
+        }
+        file_b.read_exact(&mut buffer_b[..read]).await?;
+        if buffer_a[..read] != buffer_b[..read] {
+            return Ok(false);
+        }
+    }
 }
 
 // to prevent directory traversal attacks we ensure the path consists of exactly one normal
@@ -75,7 +399,128 @@ fn path_is_valid(path: &str) -> bool {
     components.count() == 1
 }
 
+// streams a stored package back out for apt, which expects plain-text errors rather than JSON,
+// so unlike the API handlers this one does not return ApiError
+pub async fn download_package(Path(path): Path<String>) -> Result<Response, (StatusCode, String)> {
+    if !path_is_valid(&path) {
+        return Err((StatusCode::BAD_REQUEST, "Invalid path".to_owned()));
+    }
+    // only finished packages are served, never partial uploads
+    if !package_filename_is_valid(&path) {
+        return Err((StatusCode::NOT_FOUND, "Not found".to_owned()));
+    }
+
+    let file = match File::open(std::path::Path::new(UPLOADS_DIRECTORY).join(&path)).await {
+        Ok(file) => file,
+        Err(err) if err.kind() == io::ErrorKind::NotFound => {
+            return Err((StatusCode::NOT_FOUND, "Not found".to_owned()))
+        }
+        Err(err) => return Err((StatusCode::INTERNAL_SERVER_ERROR, err.to_string())),
+    };
+    let length = file
+        .metadata()
+        .await
+        .map_err(|err| (StatusCode::INTERNAL_SERVER_ERROR, err.to_string()))?
+        .len();
+
+    let headers = [
+        (header::CONTENT_TYPE, "application/vnd.debian.binary-package".to_owned()),
+        (header::CONTENT_LENGTH, length.to_string()),
+    ];
+    Ok((headers, Body::from_stream(ReaderStream::new(file))).into_response())
+}
+
+// checks the filename follows the canonical `<name>_<version>_<architecture>.deb` (or `.udeb`)
+// layout, i.e. `^[a-z0-9][a-z0-9+.-]+_[^_]+_[a-z0-9-]+\.u?deb$`
+fn package_filename_is_valid(filename: &str) -> bool {
+    let Some(stem) = filename.strip_suffix(".deb").or_else(|| filename.strip_suffix(".udeb")) else {
+        return false;
+    };
+    let parts: Vec<&str> = stem.split('_').collect();
+    let [name, version, architecture] = parts[..] else {
+        return false;
+    };
+
+    let name_is_valid = name.len() >= 2
+        && name.starts_with(|c: char| c.is_ascii_lowercase() || c.is_ascii_digit())
+        && name.chars().all(|c| c.is_ascii_lowercase() || c.is_ascii_digit() || "+.-".contains(c));
+    let architecture_is_valid = !architecture.is_empty()
+        && architecture.chars().all(|c| c.is_ascii_lowercase() || c.is_ascii_digit() || c == '-');
+
+    name_is_valid && !version.is_empty() && architecture_is_valid
+}
+
 pub async fn get_packages() -> Json<Package> {
     let p = Package{name: "python3".to_string(), version: "1.2.3".to_string(), hash: "aoaeuaoue".to_string()};
     Json(p)
 }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::tests::debian_package;
+
+    async fn check_package(name: &str, contents: &[u8]) -> bool {
+        let path = std::env::temp_dir().join(format!("zorro-test-{}-{}", std::process::id(), name));
+        tokio::fs::write(&path, contents).await.unwrap();
+        let result = is_debian_package(&path).await.unwrap();
+        tokio::fs::remove_file(&path).await.unwrap();
+        result
+    }
+
+    #[tokio::test]
+    async fn well_formed_package_is_a_debian_package() {
+        assert!(check_package("well-formed.deb", &debian_package(b"data")).await);
+        // odd sized members are padded
+        assert!(check_package("padded.deb", &debian_package(b"odd")).await);
+    }
+
+    #[tokio::test]
+    async fn malformed_archives_are_not_debian_packages() {
+        let package = debian_package(b"data");
+        assert!(!check_package("truncated.deb", &package[..package.len() - 2]).await);
+        assert!(!check_package("trailing.deb", &[&package[..], b"trailing"].concat()).await);
+        assert!(!check_package("not-ar.deb", b"not an ar archive").await);
+
+        // everything before the data.tar.xz member
+        let without_data = &package[..package.len() - 4 - 60];
+        assert!(!check_package("without-data.deb", without_data).await);
+    }
+
+    #[test]
+    fn well_formed_package_filenames_are_valid() {
+        assert!(package_filename_is_valid("python3_3.11.2-1_amd64.deb"));
+        assert!(package_filename_is_valid("libc6-udeb_2.36-9_arm64.udeb"));
+        assert!(package_filename_is_valid("g++_4:12.2.0-3_all.deb"));
+    }
+
+    #[test]
+    fn malformed_package_filenames_are_invalid() {
+        assert!(!package_filename_is_valid("python3.deb"));
+        // names are at least two characters long
+        assert!(!package_filename_is_valid("a_1.0_amd64.deb"));
+        assert!(!package_filename_is_valid("Python3_1.0_amd64.deb"));
+        assert!(!package_filename_is_valid("python3_1.0_AMD64.deb"));
+        assert!(!package_filename_is_valid("python3_1.0_extra_amd64.deb"));
+        assert!(!package_filename_is_valid("python3__amd64.deb"));
+        assert!(!package_filename_is_valid("python3_1.0_amd64.rpm"));
+        assert!(!package_filename_is_valid(".python3_1.0_amd64.deb"));
+    }
+
+    #[tokio::test]
+    async fn only_partial_uploads_are_swept() {
+        let directory = std::env::temp_dir().join(format!("zorro-test-{}-sweep", std::process::id()));
+        tokio::fs::create_dir_all(&directory).await.unwrap();
+        tokio::fs::write(directory.join(".x.deb.0.part"), b"partial").await.unwrap();
+        tokio::fs::write(directory.join("x_1.0_amd64.deb"), b"finished").await.unwrap();
+
+        let removed = remove_partial_uploads_from(&directory).await.unwrap();
+        let partial_left = directory.join(".x.deb.0.part").exists();
+        let finished_left = directory.join("x_1.0_amd64.deb").exists();
+        tokio::fs::remove_dir_all(&directory).await.unwrap();
+
+        assert_eq!(removed, 1);
+        assert!(!partial_left);
+        assert!(finished_left);
+    }
+}
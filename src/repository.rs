@@ -0,0 +1,450 @@
+use axum::{
+    extract::{rejection::JsonRejection, Path, State},
+    http::StatusCode,
+    response::Json,
+};
+use serde::{Deserialize, Serialize};
+use std::collections::BTreeMap;
+use std::fmt;
+use std::path::PathBuf;
+use std::sync::{Arc, PoisonError, RwLock, RwLockReadGuard};
+
+use crate::error::ApiError;
+
+#[derive(Serialize, Deserialize, Clone)]
+pub struct Distribution {
+    pub components: Vec<String>,
+    pub architectures: Vec<String>,
+}
+
+/// Body of `POST /v1/distributions`, the distribution fields sit next to its name.
+#[derive(Deserialize)]
+pub struct NewDistribution {
+    pub name: String,
+    #[serde(flatten)]
+    pub distribution: Distribution,
+}
+
+/// A distribution flattened into the shape a "select a target suite" list needs.
+#[derive(Serialize)]
+pub struct Suite {
+    pub name: String,
+    pub components: Vec<String>,
+    pub architectures: Vec<String>,
+}
+
+/// PEM encoded certificate chain and private key used to serve HTTPS directly.
+#[derive(Serialize, Deserialize, Clone)]
+#[serde(deny_unknown_fields)]
+pub struct TlsConfig {
+    pub cert: String,
+    pub key: String,
+}
+
+/// The archive layout described by the repository structure configuration. Unknown keys are
+/// rejected, a misspelled `api_tokens` must not quietly turn authentication off.
+#[derive(Deserialize)]
+#[serde(deny_unknown_fields)]
+pub struct DebianArchive {
+    // distributions can be registered at runtime, use `distributions()` to read them
+    distributions: RwLock<BTreeMap<String, Distribution>>,
+    // when absent the server speaks plain HTTP
+    #[serde(default)]
+    pub tls: Option<TlsConfig>,
+    // bearer tokens accepted by the write endpoints, empty disables authentication
+    #[serde(default)]
+    pub api_tokens: Vec<String>,
+    // where runtime changes are written back to
+    #[serde(skip)]
+    config_path: PathBuf,
+    // serializes runtime changes so concurrent writers can't persist an older snapshot last
+    #[serde(skip)]
+    config_writer: tokio::sync::Mutex<()>,
+}
+
+// what gets written back to the configuration file
+#[derive(Serialize)]
+struct ConfigFile {
+    distributions: BTreeMap<String, Distribution>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    tls: Option<TlsConfig>,
+    #[serde(skip_serializing_if = "Vec::is_empty")]
+    api_tokens: Vec<String>,
+}
+
+#[derive(Debug)]
+pub enum ConfigError {
+    CouldNotReadConfiguration {
+        path: String,
+        source: std::io::Error,
+    },
+    CouldNotDecodeConfiguration {
+        path: String,
+        // dotted path to the offending entry, e.g. `distributions.stable.architectures`
+        field: Option<String>,
+        line: Option<usize>,
+        column: Option<usize>,
+        source: serde_yaml::Error,
+    },
+    ValidationFailed {
+        path: String,
+        problems: Vec<String>,
+    },
+}
+
+impl fmt::Display for ConfigError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            ConfigError::CouldNotReadConfiguration { path, source } => {
+                write!(f, "Could not read configuration {}: {}", path, source)
+            }
+            ConfigError::CouldNotDecodeConfiguration { path, field, line, column, source } => {
+                write!(f, "Could not decode configuration {}", path)?;
+                if let (Some(line), Some(column)) = (line, column) {
+                    write!(f, " at line {}, column {}", line, column)?;
+                }
+                if let Some(field) = field {
+                    write!(f, " in `{}`", field)?;
+                }
+                // serde_yaml repeats the field and location in its own message. Only the exact
+                // forms written above are stripped, should its format change the message just
+                // ends up repeating them
+                let message = source.to_string();
+                let mut reason = message.as_str();
+                if let (Some(line), Some(column)) = (line, column) {
+                    reason = reason.strip_suffix(&format!(" at line {} column {}", line, column)).unwrap_or(reason);
+                }
+                if let Some(field) = field {
+                    reason = reason.strip_prefix(&format!("{}: ", field)).unwrap_or(reason);
+                }
+                write!(f, ": {}", reason)
+            }
+            ConfigError::ValidationFailed { path, problems } => {
+                write!(f, "Invalid configuration {}:", path)?;
+                for problem in problems {
+                    write!(f, "\n  - {}", problem)?;
+                }
+                Ok(())
+            }
+        }
+    }
+}
+
+impl std::error::Error for ConfigError {
+    fn source(&self) -> Option<&(dyn std::error::Error + 'static)> {
+        match self {
+            ConfigError::CouldNotReadConfiguration { source, .. } => Some(source),
+            ConfigError::CouldNotDecodeConfiguration { source, .. } => Some(source),
+            ConfigError::ValidationFailed { .. } => None,
+        }
+    }
+}
+
+impl DebianArchive {
+    pub fn new(config_path: &str) -> Result<Self, ConfigError> {
+        let contents = std::fs::read_to_string(config_path).map_err(|source| ConfigError::CouldNotReadConfiguration {
+            path: config_path.to_owned(),
+            source,
+        })?;
+
+        let mut archive: Self = serde_path_to_error::deserialize(serde_yaml::Deserializer::from_str(&contents)).map_err(|err| {
+            let field = err.path().to_string();
+            let source = err.into_inner();
+            let location = source.location();
+            ConfigError::CouldNotDecodeConfiguration {
+                path: config_path.to_owned(),
+                field: (field != ".").then_some(field),
+                line: location.as_ref().map(|location| location.line()),
+                column: location.as_ref().map(|location| location.column()),
+                source,
+            }
+        })?;
+        archive.config_path = PathBuf::from(config_path);
+
+        archive.validate().map_err(|problems| ConfigError::ValidationFailed {
+            path: config_path.to_owned(),
+            problems,
+        })?;
+        Ok(archive)
+    }
+
+    /// Checks the parts of the configuration that decode fine but can't be served, collecting
+    /// every problem instead of stopping at the first.
+    pub fn validate(&self) -> Result<(), Vec<String>> {
+        let mut problems = Vec::new();
+
+        let distributions = self.distributions();
+        if distributions.is_empty() {
+            problems.push("no distributions are configured".to_owned());
+        }
+        for (name, distribution) in distributions.iter() {
+            problems.extend(validate_distribution(name, distribution));
+        }
+
+        if self.api_tokens.iter().any(|token| token.is_empty()) {
+            problems.push("api_tokens contains an empty token".to_owned());
+        }
+
+        if problems.is_empty() {
+            Ok(())
+        } else {
+            Err(problems)
+        }
+    }
+
+    pub fn distributions(&self) -> RwLockReadGuard<'_, BTreeMap<String, Distribution>> {
+        // the map is only ever replaced whole, so a poisoned lock still holds a consistent value
+        self.distributions.read().unwrap_or_else(PoisonError::into_inner)
+    }
+
+    /// Registers a new distribution and writes it back to the configuration file so it
+    /// survives a restart. The in-memory set only changes once the file has been written.
+    /// Returns the name it was stored under, surrounding whitespace is trimmed.
+    pub async fn add_distribution(&self, name: &str, distribution: Distribution) -> Result<String, ApiError> {
+        let name = name.trim().to_owned();
+        let problems = validate_distribution(&name, &distribution);
+        if !problems.is_empty() {
+            return Err(ApiError::InvalidDistribution(problems));
+        }
+
+        let _writer = self.config_writer.lock().await;
+        if self.distributions().contains_key(&name) {
+            return Err(ApiError::DistributionExists(name));
+        }
+        let mut distributions = self.distributions().clone();
+        distributions.insert(name.clone(), distribution);
+
+        self.save(&distributions).await?;
+        *self.distributions.write().unwrap_or_else(PoisonError::into_inner) = distributions;
+        Ok(name)
+    }
+
+    /// Removes a distribution and writes the change back to the configuration file. The last
+    /// distribution can't be removed since the configuration would no longer validate.
+    pub async fn remove_distribution(&self, name: &str) -> Result<Distribution, ApiError> {
+        let _writer = self.config_writer.lock().await;
+        let mut distributions = self.distributions().clone();
+        let Some(removed) = distributions.remove(name) else {
+            return Err(ApiError::DistributionNotFound(name.to_owned()));
+        };
+        if distributions.is_empty() {
+            return Err(ApiError::LastDistribution(name.to_owned()));
+        }
+
+        self.save(&distributions).await?;
+        *self.distributions.write().unwrap_or_else(PoisonError::into_inner) = distributions;
+        Ok(removed)
+    }
+
+    // replaces the configuration file atomically, comments in the original are not preserved
+    async fn save(&self, distributions: &BTreeMap<String, Distribution>) -> std::io::Result<()> {
+        let config = ConfigFile {
+            distributions: distributions.clone(),
+            tls: self.tls.clone(),
+            api_tokens: self.api_tokens.clone(),
+        };
+        let contents = serde_yaml::to_string(&config).map_err(std::io::Error::other)?;
+
+        let mut partial = self.config_path.clone().into_os_string();
+        partial.push(".part");
+        tokio::fs::write(&partial, contents).await?;
+        tokio::fs::rename(&partial, &self.config_path).await
+    }
+}
+
+fn validate_distribution(name: &str, distribution: &Distribution) -> Vec<String> {
+    let mut problems = Vec::new();
+    if name.trim().is_empty() {
+        problems.push("a distribution has an empty name".to_owned());
+    } else if !name_is_valid(name) {
+        problems.push(format!(
+            "distribution `{}` needs a name made of letters, digits, `.`, `_` and `-` that doesn't start with `.`",
+            name
+        ));
+    }
+    check_entries(name, "component", &distribution.components, &mut problems);
+    check_entries(name, "architecture", &distribution.architectures, &mut problems);
+    problems
+}
+
+// the name becomes the `dists/<name>` directory, so it must stay a single plain path component
+fn name_is_valid(name: &str) -> bool {
+    !name.starts_with('.') && name.chars().all(|c| c.is_ascii_alphanumeric() || "._-".contains(c))
+}
+
+// a distribution needs at least one component and architecture, each listed once
+fn check_entries(distribution: &str, kind: &str, entries: &[String], problems: &mut Vec<String>) {
+    if entries.is_empty() {
+        problems.push(format!("distribution `{}` has no {}s", distribution, kind));
+    }
+    for (index, entry) in entries.iter().enumerate() {
+        if entry.trim().is_empty() {
+            problems.push(format!("distribution `{}` has an empty {}", distribution, kind));
+        } else if entries[..index].contains(entry) {
+            problems.push(format!("distribution `{}` lists {} `{}` more than once", distribution, kind, entry));
+        }
+    }
+}
+
+pub async fn handle_get_repositories(State(archive): State<Arc<DebianArchive>>) -> Json<BTreeMap<String, Distribution>> {
+    Json(archive.distributions().clone())
+}
+
+pub async fn handle_get_suites(State(archive): State<Arc<DebianArchive>>) -> Json<Vec<Suite>> {
+    let suites = archive
+        .distributions()
+        .iter()
+        .map(|(name, distribution)| Suite {
+            name: name.clone(),
+            components: distribution.components.clone(),
+            architectures: distribution.architectures.clone(),
+        })
+        .collect();
+    Json(suites)
+}
+
+pub async fn handle_add_distribution(
+    State(archive): State<Arc<DebianArchive>>,
+    body: Result<Json<NewDistribution>, JsonRejection>,
+) -> Result<(StatusCode, Json<Suite>), ApiError> {
+    let Json(NewDistribution { name, distribution }) = body.map_err(|rejection| match rejection.status() {
+        StatusCode::PAYLOAD_TOO_LARGE => ApiError::RequestBodyTooLarge,
+        _ => ApiError::InvalidRequestBody(rejection.body_text()),
+    })?;
+
+    let (components, architectures) = (distribution.components.clone(), distribution.architectures.clone());
+    let name = archive.add_distribution(&name, distribution).await?;
+    Ok((StatusCode::CREATED, Json(Suite { name, components, architectures })))
+}
+
+pub async fn handle_remove_distribution(
+    State(archive): State<Arc<DebianArchive>>,
+    Path(name): Path<String>,
+) -> Result<Json<Suite>, ApiError> {
+    let removed = archive.remove_distribution(&name).await?;
+    Ok(Json(Suite { name, components: removed.components, architectures: removed.architectures }))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::tests::{config_file, CONFIG};
+
+    #[test]
+    fn unknown_configuration_keys_are_rejected() {
+        for key in ["api_token: [secret]", "tls_cert: cert.pem"] {
+            let path = config_file(&format!("{}{}\n", CONFIG, key));
+            let err = DebianArchive::new(&path).err().expect("unknown key was accepted");
+            assert!(matches!(err, ConfigError::CouldNotDecodeConfiguration { .. }));
+            assert!(err.to_string().contains("unknown field"), "{}", err);
+        }
+    }
+
+    #[test]
+    fn decode_errors_name_the_field_and_location() {
+        let path = config_file("distributions:\n  stable:\n    components: [main]\n");
+        let err = DebianArchive::new(&path).err().expect("incomplete distribution was accepted");
+
+        assert!(matches!(
+            &err,
+            ConfigError::CouldNotDecodeConfiguration { field: Some(field), line: Some(3), column: Some(5), .. }
+                if field == "distributions.stable"
+        ));
+        assert_eq!(
+            err.to_string(),
+            format!(
+                "Could not decode configuration {} at line 3, column 5 in `distributions.stable`: missing field `architectures`",
+                path
+            )
+        );
+    }
+
+    // the problems `validate` reports for `config`
+    fn problems(config: &str) -> Vec<String> {
+        match DebianArchive::new(&config_file(config)) {
+            Err(ConfigError::ValidationFailed { problems, .. }) => problems,
+            Err(err) => panic!("unexpected error: {}", err),
+            Ok(_) => Vec::new(),
+        }
+    }
+
+    #[test]
+    fn valid_configuration_has_no_problems() {
+        assert!(problems(CONFIG).is_empty());
+    }
+
+    #[test]
+    fn no_distributions() {
+        assert_eq!(problems("distributions: {}\n"), ["no distributions are configured"]);
+    }
+
+    #[test]
+    fn empty_distribution_name() {
+        assert_eq!(
+            problems("distributions:\n  '':\n    components: [main]\n    architectures: [amd64]\n"),
+            ["a distribution has an empty name"]
+        );
+    }
+
+    #[test]
+    fn no_components_or_architectures() {
+        assert_eq!(
+            problems("distributions:\n  stable:\n    components: []\n    architectures: []\n"),
+            ["distribution `stable` has no components", "distribution `stable` has no architectures"]
+        );
+    }
+
+    #[test]
+    fn empty_entries() {
+        assert_eq!(
+            problems("distributions:\n  stable:\n    components: [main, '']\n    architectures: [' ']\n"),
+            ["distribution `stable` has an empty component", "distribution `stable` has an empty architecture"]
+        );
+    }
+
+    #[test]
+    fn duplicate_entries() {
+        assert_eq!(
+            problems("distributions:\n  stable:\n    components: [main, main]\n    architectures: [amd64, arm64, amd64]\n"),
+            [
+                "distribution `stable` lists component `main` more than once",
+                "distribution `stable` lists architecture `amd64` more than once",
+            ]
+        );
+    }
+
+    #[test]
+    fn empty_api_token() {
+        assert_eq!(problems(&format!("{}api_tokens: [secret, '']\n", CONFIG)), ["api_tokens contains an empty token"]);
+    }
+
+    #[test]
+    fn every_problem_is_reported() {
+        let problems = problems(
+            "distributions:\n  stable:\n    components: []\n    architectures: [amd64]\n  testing:\n    components: [main]\n    architectures: []\napi_tokens: ['']\n",
+        );
+        assert_eq!(
+            problems,
+            [
+                "distribution `stable` has no components",
+                "distribution `testing` has no architectures",
+                "api_tokens contains an empty token",
+            ]
+        );
+    }
+
+    #[test]
+    fn unsafe_distribution_names() {
+        for name in ["../etc", "stable/updates", ".hidden", "sid experimental"] {
+            let config = format!("distributions:\n  '{}':\n    components: [main]\n    architectures: [amd64]\n", name);
+            assert_eq!(
+                problems(&config),
+                [format!(
+                    "distribution `{}` needs a name made of letters, digits, `.`, `_` and `-` that doesn't start with `.`",
+                    name
+                )]
+            );
+        }
+        assert!(problems(&CONFIG.replace("stable", "bookworm-backports_1.0")).is_empty());
+    }
+}
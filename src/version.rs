@@ -0,0 +1,124 @@
+use std::cmp::Ordering;
+
+/// Compares two Debian version strings the way `dpkg --compare-versions` does: epoch first,
+/// then the upstream version, then the Debian revision. A `~` sorts before anything, even the
+/// end of the string, so `1.0~rc1` is older than `1.0`.
+///
+/// The comparison never fails: where dpkg would reject an epoch that isn't a number, such as
+/// `a:1.0`, it is treated as epoch 0 and the rest compared as usual.
+pub fn compare(a: &str, b: &str) -> Ordering {
+    let (epoch_a, upstream_a, revision_a) = split(a);
+    let (epoch_b, upstream_b, revision_b) = split(b);
+
+    epoch_a
+        .cmp(&epoch_b)
+        .then_with(|| compare_fragment(upstream_a, upstream_b))
+        .then_with(|| compare_fragment(revision_a, revision_b))
+}
+
+// splits `[epoch:]upstream[-revision]`, the revision starts after the last hyphen. A malformed
+// epoch falls back to 0, see `compare`
+fn split(version: &str) -> (u64, &str, &str) {
+    let (epoch, rest) = match version.split_once(':') {
+        Some((epoch, rest)) => (epoch.parse().unwrap_or(0), rest),
+        None => (0, version),
+    };
+    match rest.rsplit_once('-') {
+        Some((upstream, revision)) => (epoch, upstream, revision),
+        None => (epoch, rest, ""),
+    }
+}
+
+// weight of a non-digit character, `0` stands for both digits and the end of the string
+fn order(c: u8) -> i32 {
+    match c {
+        0 => 0,
+        c if c.is_ascii_digit() => 0,
+        c if c.is_ascii_alphabetic() => c as i32,
+        b'~' => -1,
+        c => c as i32 + 256,
+    }
+}
+
+// alternates between comparing non-digit runs character by character and digit runs numerically
+fn compare_fragment(a: &str, b: &str) -> Ordering {
+    let (a, b) = (a.as_bytes(), b.as_bytes());
+    let at = |s: &[u8], i: usize| s.get(i).copied().unwrap_or(0);
+    let (mut i, mut j) = (0, 0);
+
+    while i < a.len() || j < b.len() {
+        while (i < a.len() && !a[i].is_ascii_digit()) || (j < b.len() && !b[j].is_ascii_digit()) {
+            let (order_a, order_b) = (order(at(a, i)), order(at(b, j)));
+            if order_a != order_b {
+                return order_a.cmp(&order_b);
+            }
+            i += 1;
+            j += 1;
+        }
+
+        while at(a, i) == b'0' {
+            i += 1;
+        }
+        while at(b, j) == b'0' {
+            j += 1;
+        }
+
+        let mut first_difference = Ordering::Equal;
+        while at(a, i).is_ascii_digit() && at(b, j).is_ascii_digit() {
+            if first_difference == Ordering::Equal {
+                first_difference = a[i].cmp(&b[j]);
+            }
+            i += 1;
+            j += 1;
+        }
+        // the longer run of digits is the bigger number
+        if at(a, i).is_ascii_digit() {
+            return Ordering::Greater;
+        }
+        if at(b, j).is_ascii_digit() {
+            return Ordering::Less;
+        }
+        if first_difference != Ordering::Equal {
+            return first_difference;
+        }
+    }
+
+    Ordering::Equal
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn compares_like_dpkg() {
+        let cases = [
+            ("1.0~rc1", "1.0", Ordering::Less),
+            ("1.0~~", "1.0~", Ordering::Less),
+            ("2.10", "2.9", Ordering::Greater),
+            ("1.01", "1.1", Ordering::Equal),
+            ("1.0+b1", "1.0", Ordering::Greater),
+            ("1.0a", "1.0+", Ordering::Less),
+            ("1.0-1", "1.0-2", Ordering::Less),
+            ("1.0", "1.0-0", Ordering::Equal),
+            ("1.0-beta-2", "1.0-beta-10", Ordering::Less),
+        ];
+        for (a, b, expected) in cases {
+            assert_eq!(compare(a, b), expected, "{} vs {}", a, b);
+            assert_eq!(compare(b, a), expected.reverse(), "{} vs {}", b, a);
+        }
+    }
+
+    #[test]
+    fn epoch_takes_precedence() {
+        assert_eq!(compare("1:1.0", "2.0"), Ordering::Greater);
+        assert_eq!(compare("1:1.0", "2:0.1"), Ordering::Less);
+        assert_eq!(compare("0:1.0", "1.0"), Ordering::Equal);
+    }
+
+    #[test]
+    fn malformed_epoch_is_zero() {
+        assert_eq!(compare("a:1.0", "1.0"), Ordering::Equal);
+        assert_eq!(compare("a:1.0", "1:1.0"), Ordering::Less);
+    }
+}